@@ -1,8 +1,10 @@
 //! The default implementation of a WebSocket Receiver.
 
-use std::io::Read;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
 use std::io::Result as IoResult;
 
+use flate2::{Decompress, FlushDecompress, Status};
 use hyper::buffer::BufReader;
 use uuid::Uuid;
 
@@ -12,10 +14,77 @@ use ws;
 use ws::receiver::Receiver as ReceiverTrait;
 use ws::receiver::{MessageIterator, DataFrameIterator};
 use ws::util::header::{DataFrameHeader, ReaderState};
-use message::OwnedMessage;
+use message::{CloseData, OwnedMessage};
 use stream::sync::{AsTcpStream, Stream};
 pub use stream::sync::Shutdown;
 
+/// Default maximum payload size, in bytes, of a single data frame.
+const DEFAULT_MAX_DATAFRAME_SIZE: usize = 100 * 1024 * 1024;
+/// Default maximum cumulative payload size, in bytes, of a message assembled
+/// from one or more data frames.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 200 * 1024 * 1024;
+/// Default maximum number of data frames that may be buffered for a single message.
+const DEFAULT_MAX_DATAFRAMES_PER_MESSAGE: usize = 1_000_000;
+/// Approximate per-frame header overhead accounted for when bounding the cumulative
+/// size of a message, so that floods of tiny frames are also bounded.
+const FRAME_OVERHEAD_BYTES: usize = 14;
+/// The bytes RFC 7692 says to append to a compressed message before inflating it,
+/// restoring the trailing empty stored block the sender stripped off.
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// Tracks the data *and* control frames received while assembling one message,
+/// enforcing `max_dataframes_per_message`/`max_message_size` the same way regardless
+/// of whether frames are buffered (`recv_message_dataframes`) or streamed
+/// (`MessageReader`). Counting control frames too means a flood of interleaved Pings
+/// can't bypass the per-message frame cap.
+#[derive(Debug, Default)]
+struct MessageSizeTracker {
+	frame_count: usize,
+	total_size: usize,
+}
+
+impl MessageSizeTracker {
+	fn new() -> MessageSizeTracker {
+		MessageSizeTracker::default()
+	}
+
+	/// Accounts for a just-received frame's payload length, erroring out before the
+	/// caller does anything with the frame if doing so exceeds either configured limit.
+	fn account(
+		&mut self,
+		frame_len: usize,
+		max_dataframes_per_message: usize,
+		max_message_size: usize,
+	) -> WebSocketResult<()> {
+		self.frame_count += 1;
+		if self.frame_count > max_dataframes_per_message {
+			return Err(WebSocketError::SizeLimitExceeded(
+				"too many data frames buffered for a single message",
+			));
+		}
+
+		self.total_size += frame_len + FRAME_OVERHEAD_BYTES;
+		if self.total_size > max_message_size {
+			return Err(WebSocketError::SizeLimitExceeded(
+				"message exceeds the configured maximum size",
+			));
+		}
+
+		Ok(())
+	}
+}
+
+/// The permessage-deflate (RFC 7692) mode negotiated for this connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeflateMode {
+	/// The extension was not negotiated; frames are read as-is.
+	Disabled,
+	/// The extension is active and the inflate dictionary is reset after every message.
+	NoContextTakeover,
+	/// The extension is active and a single inflate stream persists across messages.
+	ContextTakeover,
+}
+
 #[derive(Debug, Default)]
 /// A state for a reader to contain a buffer for incomplete reads to recover.
 pub struct PacketState {
@@ -56,6 +125,14 @@ impl<R> Reader<R>
 		self.receiver.recv_dataframe(&mut self.stream, uuid)
 	}
 
+	/// Attempts to read a single data frame without blocking. See
+	/// [`Receiver::try_recv_dataframe`].
+	///
+	/// [`Receiver::try_recv_dataframe`]: struct.Receiver.html#method.try_recv_dataframe
+	pub fn try_recv_dataframe(&mut self) -> WebSocketResult<Option<DataFrame>> {
+		self.receiver.try_recv_dataframe(&mut self.stream)
+	}
+
 	/// Returns an iterator over incoming data frames.
 	pub fn incoming_dataframes(&mut self) -> DataFrameIterator<Receiver, BufReader<R>> {
 		self.receiver.incoming_dataframes(&mut self.stream)
@@ -71,6 +148,50 @@ impl<R> Reader<R>
 	pub fn incoming_messages<'a>(&'a mut self) -> MessageIterator<'a, Receiver, BufReader<R>> {
 		self.receiver.incoming_messages(&mut self.stream)
 	}
+
+	/// Sets the maximum allowed payload size, in bytes, of a single data frame.
+	pub fn set_max_dataframe_size(&mut self, size: usize) {
+		self.receiver.set_max_dataframe_size(size);
+	}
+
+	/// Sets the maximum allowed cumulative payload size, in bytes, of a message
+	/// assembled from one or more data frames.
+	pub fn set_max_message_size(&mut self, size: usize) {
+		self.receiver.set_max_message_size(size);
+	}
+
+	/// Sets the maximum number of data frames that may be buffered for a single message.
+	pub fn set_max_dataframes_per_message(&mut self, count: usize) {
+		self.receiver.set_max_dataframes_per_message(count);
+	}
+
+	/// Enables or disables transparent ping/pong and close handling. See
+	/// [`Receiver::set_auto_respond_to_control_frames`] for details.
+	///
+	/// [`Receiver::set_auto_respond_to_control_frames`]: struct.Receiver.html#method.set_auto_respond_to_control_frames
+	pub fn set_auto_respond_to_control_frames(&mut self, auto_respond: bool) {
+		self.receiver.set_auto_respond_to_control_frames(auto_respond);
+	}
+
+	/// Drains and returns the control frame responses (pongs, close echoes) queued up
+	/// by auto-respond mode so they can be sent out over the writer side of a split
+	/// connection.
+	pub fn take_pending_responses(&mut self) -> VecDeque<OwnedMessage> {
+		self.receiver.take_pending_responses()
+	}
+
+	/// Sets the permessage-deflate mode negotiated during the handshake.
+	pub fn set_deflate_mode(&mut self, mode: DeflateMode) {
+		self.receiver.set_deflate_mode(mode);
+	}
+
+	/// Returns a streaming reader over the payload of the next incoming data message.
+	/// See [`Receiver::message_reader`].
+	///
+	/// [`Receiver::message_reader`]: struct.Receiver.html#method.message_reader
+	pub fn message_reader<'a>(&'a mut self) -> MessageReader<'a, BufReader<R>> {
+		self.receiver.message_reader(&mut self.stream)
+	}
 }
 
 impl<S> Reader<S>
@@ -97,6 +218,13 @@ pub struct Receiver {
 	packet_state: PacketState,
 	reader_state: ReaderState,
 	uuid: Uuid,
+	max_dataframe_size: usize,
+	max_message_size: usize,
+	max_dataframes_per_message: usize,
+	auto_respond_control_frames: bool,
+	pending_responses: VecDeque<OwnedMessage>,
+	deflate_mode: DeflateMode,
+	inflater: Option<Decompress>,
 }
 
 impl Receiver {
@@ -108,7 +236,296 @@ impl Receiver {
 			packet_state: PacketState::default(),
 			reader_state: ReaderState::new(),
 			uuid: uuid,
+			max_dataframe_size: DEFAULT_MAX_DATAFRAME_SIZE,
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+			max_dataframes_per_message: DEFAULT_MAX_DATAFRAMES_PER_MESSAGE,
+			auto_respond_control_frames: false,
+			pending_responses: VecDeque::new(),
+			deflate_mode: DeflateMode::Disabled,
+			inflater: None,
+		}
+	}
+
+	/// Sets the maximum allowed payload size, in bytes, of a single data frame.
+	pub fn set_max_dataframe_size(&mut self, size: usize) {
+		self.max_dataframe_size = size;
+	}
+
+	/// Sets the maximum allowed cumulative payload size, in bytes, of a message
+	/// assembled from one or more data frames.
+	pub fn set_max_message_size(&mut self, size: usize) {
+		self.max_message_size = size;
+	}
+
+	/// Sets the maximum number of data frames that may be buffered for a single message.
+	pub fn set_max_dataframes_per_message(&mut self, count: usize) {
+		self.max_dataframes_per_message = count;
+	}
+
+	/// When enabled, incoming Ping frames are transparently answered with a matching
+	/// Pong (queued in [`pending_responses`]) rather than surfaced to the caller;
+	/// `recv_message_dataframes` keeps reading until it has a full data message
+	/// instead of returning the Ping. A Close frame is echoed back (a matching Close is
+	/// queued) and is still returned to the caller. Disabled by default so existing
+	/// callers see no behavior change.
+	///
+	/// [`pending_responses`]: #method.take_pending_responses
+	pub fn set_auto_respond_to_control_frames(&mut self, auto_respond: bool) {
+		self.auto_respond_control_frames = auto_respond;
+	}
+
+	/// Drains and returns any queued auto-responses (pongs, close echoes) generated
+	/// while auto-respond mode is enabled. Intended to be polled by the writer half
+	/// of a split connection and flushed out over the wire.
+	pub fn take_pending_responses(&mut self) -> VecDeque<OwnedMessage> {
+		::std::mem::replace(&mut self.pending_responses, VecDeque::new())
+	}
+
+	/// Queues an auto-generated control-frame response, bounded by
+	/// `max_dataframes_per_message` so a peer that floods Pings faster than the writer
+	/// side drains `pending_responses` can't grow this queue without bound.
+	fn push_pending_response(&mut self, message: OwnedMessage) -> WebSocketResult<()> {
+		if self.pending_responses.len() >= self.max_dataframes_per_message {
+			return Err(WebSocketError::SizeLimitExceeded(
+				"too many queued control-frame responses pending flush",
+			));
+		}
+
+		self.pending_responses.push_back(message);
+		Ok(())
+	}
+
+	/// Sets the permessage-deflate mode negotiated during the handshake. Switching
+	/// away from [`DeflateMode::Disabled`] drops any persisted inflate stream so the
+	/// next compressed message starts from a fresh dictionary.
+	///
+	/// [`DeflateMode::Disabled`]: enum.DeflateMode.html#variant.Disabled
+	pub fn set_deflate_mode(&mut self, mode: DeflateMode) {
+		self.deflate_mode = mode;
+		self.inflater = None;
+	}
+
+	/// Inflates a permessage-deflate compressed message payload, enforcing
+	/// `max_message_size` against the *decompressed* output to guard against
+	/// decompression bombs.
+	fn inflate_message(&mut self, compressed: &[u8]) -> WebSocketResult<Vec<u8>> {
+		if self.deflate_mode == DeflateMode::NoContextTakeover || self.inflater.is_none() {
+			self.inflater = Some(Decompress::new(false));
+		}
+		let inflater = self.inflater.as_mut().expect("inflater initialized above");
+
+		let mut input = Vec::with_capacity(compressed.len() + DEFLATE_TRAILER.len());
+		input.extend_from_slice(compressed);
+		input.extend_from_slice(&DEFLATE_TRAILER);
+
+		let mut output = Vec::new();
+		let mut chunk = [0u8; 8192];
+
+		// `total_in`/`total_out` are cumulative over the inflater's whole lifetime, not
+		// just this call, since `ContextTakeover` reuses the same `Decompress` across
+		// messages. Track the offsets at which *this* call started so we index into
+		// `input` (which only holds the current message's bytes) correctly.
+		let start_in = inflater.total_in();
+		let start_out = inflater.total_out();
+
+		loop {
+			let consumed = (inflater.total_in() - start_in) as usize;
+			let produced = (inflater.total_out() - start_out) as usize;
+
+			let status = inflater
+				.decompress(&input[consumed..], &mut chunk, FlushDecompress::Sync)
+				.map_err(|_| {
+					WebSocketError::CompressionError("malformed permessage-deflate stream")
+				})?;
+
+			let new_produced = (inflater.total_out() - start_out) as usize;
+			output.extend_from_slice(&chunk[..new_produced - produced]);
+
+			if output.len() > self.max_message_size {
+				return Err(WebSocketError::SizeLimitExceeded(
+					"decompressed message exceeds the configured maximum size",
+				));
+			}
+
+			let consumed_all_input = (inflater.total_in() - start_in) as usize >= input.len();
+			match status {
+				Status::StreamEnd => break,
+				_ if consumed_all_input => break,
+				_ => continue,
+			}
+		}
+
+		Ok(output)
+	}
+
+	/// Attempts to read a single data frame without blocking. If the underlying
+	/// reader isn't ready yet (`io::ErrorKind::WouldBlock`), returns `Ok(None)` while
+	/// preserving the partially read header and payload bytes in `packet_state` and
+	/// `reader_state`, so the next call resumes parsing exactly where this one left
+	/// off rather than starting the frame over.
+	pub fn try_recv_dataframe<R>(&mut self, reader: &mut R) -> WebSocketResult<Option<DataFrame>>
+		where R: Read
+	{
+		let uuid = self.uuid;
+
+		match self.recv_dataframe(reader, uuid) {
+			Ok(frame) => Ok(Some(frame)),
+			Err(WebSocketError::IoError(ref e)) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
+				Ok(None)
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Returns a streaming reader over the payload of the next incoming data message.
+	/// Unlike [`recv_message`], this doesn't buffer the whole message in memory: it
+	/// yields payload bytes frame-by-frame as they arrive, transparently consuming
+	/// continuation frames until the message is finished and, when auto-respond mode
+	/// is enabled, answering any Pings interleaved with it. An interleaved Close is
+	/// echoed in auto-respond mode and then ends the read with a `ConnectionAborted`
+	/// error; with auto-respond mode disabled, any interleaved control frame is an error.
+	///
+	/// [`recv_message`]: ../ws/receiver/trait.Receiver.html#method.recv_message
+	pub fn message_reader<'a, R>(&'a mut self, reader: &'a mut R) -> MessageReader<'a, R>
+		where R: Read
+	{
+		MessageReader {
+			receiver: self,
+			reader: reader,
+			current: Vec::new(),
+			position: 0,
+			started: false,
+			compressed: false,
+			compressed_buffer: Vec::new(),
+			tracker: MessageSizeTracker::new(),
+			done: false,
+		}
+	}
+}
+
+/// A streaming reader over the payload of a single incoming message, returned by
+/// [`Receiver::message_reader`]. Reads are served directly from each arriving data
+/// frame rather than from a buffer of the whole message, except for a
+/// permessage-deflate compressed message, which must be fully received before it can
+/// be inflated.
+///
+/// [`Receiver::message_reader`]: struct.Receiver.html#method.message_reader
+pub struct MessageReader<'a, R: 'a + Read> {
+	receiver: &'a mut Receiver,
+	reader: &'a mut R,
+	current: Vec<u8>,
+	position: usize,
+	/// Whether the first frame of the message has been seen yet.
+	started: bool,
+	/// Whether the message's first frame carried the RSV1 bit, set once `started`.
+	compressed: bool,
+	/// Accumulates compressed frame payloads until the message is finished, since
+	/// permessage-deflate can only be inflated once the whole compressed stream is in.
+	compressed_buffer: Vec<u8>,
+	tracker: MessageSizeTracker,
+	done: bool,
+}
+
+impl<'a, R: 'a + Read> Read for MessageReader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		while self.position >= self.current.len() && !self.done {
+			let uuid = self.receiver.uuid;
+
+			let frame = loop {
+				let frame = self.receiver
+					.recv_dataframe(self.reader, uuid)
+					.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+
+				if (frame.opcode as u8) < 8 {
+					break frame;
+				}
+
+				// Control frame interleaved with the message's data frames.
+				if !self.receiver.auto_respond_control_frames {
+					return Err(IoError::new(
+						IoErrorKind::Other,
+						"unexpected control frame interleaved in message",
+					));
+				}
+
+				// count control frames against the per-message frame cap too, so a
+				// flood of interleaved Pings can't grow `pending_responses` without bound
+				self.tracker
+					.account(0, self.receiver.max_dataframes_per_message, self.receiver.max_message_size)
+					.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+
+				match frame.opcode {
+					Opcode::Ping => {
+						self.receiver
+							.push_pending_response(OwnedMessage::Pong(frame.data))
+							.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+					}
+					Opcode::Close => {
+						let echo = echo_close(&frame.data);
+						self.receiver
+							.push_pending_response(echo)
+							.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+						// the peer isn't sending more message data after a Close; stop
+						// waiting for it instead of looping on `recv_dataframe` forever
+						return Err(IoError::new(
+							IoErrorKind::ConnectionAborted,
+							"peer sent Close while streaming message",
+						));
+					}
+					_ => {}
+				}
+			};
+
+			if !self.started {
+				self.started = true;
+
+				if frame.opcode == Opcode::Continuation {
+					return Err(IoError::new(
+						IoErrorKind::InvalidData,
+						"Unexpected continuation data frame opcode",
+					));
+				}
+
+				self.compressed = self.receiver.deflate_mode != DeflateMode::Disabled && frame.reserved[0];
+			} else if frame.opcode != Opcode::Continuation {
+				return Err(IoError::new(
+					IoErrorKind::InvalidData,
+					"Unexpected data frame opcode",
+				));
+			}
+
+			self.tracker
+				.account(
+					frame.data.len(),
+					self.receiver.max_dataframes_per_message,
+					self.receiver.max_message_size,
+				)
+				.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+
+			if self.compressed {
+				// compressed frames can't be served until the whole message has arrived
+				self.compressed_buffer.extend_from_slice(&frame.data);
+
+				if frame.finished {
+					let data = self.receiver
+						.inflate_message(&self.compressed_buffer)
+						.map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+					self.compressed_buffer.clear();
+					self.current = data;
+					self.position = 0;
+					self.done = true;
+				}
+			} else {
+				self.current = frame.data;
+				self.position = 0;
+				self.done = frame.finished;
+			}
 		}
+
+		let n = ::std::cmp::min(buf.len(), self.current.len() - self.position);
+		buf[..n].copy_from_slice(&self.current[self.position..self.position + n]);
+		self.position += n;
+		Ok(n)
 	}
 }
 
@@ -132,6 +549,7 @@ impl ws::Receiver for Receiver {
 			uuid,
 			&mut self.packet_state,
 			&mut self.reader_state,
+			self.max_dataframe_size,
 		)
 	}
 
@@ -140,14 +558,42 @@ impl ws::Receiver for Receiver {
 		where R: Read
 	{
 		let uuid = self.uuid;
+
+		let mut tracker = MessageSizeTracker::new();
+		for frame in &self.buffer {
+			tracker.account(frame.data.len(), self.max_dataframes_per_message, self.max_message_size)?;
+		}
+
 		let mut finished = if self.buffer.is_empty() {
-			let first = self.recv_dataframe(reader, uuid)?;
+			let first = loop {
+				let frame = self.recv_dataframe(reader, uuid)?;
+				if !self.auto_respond_control_frames || (frame.opcode as u8) < 8 {
+					break frame;
+				}
+
+				// a control frame ahead of the message's first data frame is handled the
+				// same way as one interleaved with its continuation frames below
+				tracker.account(0, self.max_dataframes_per_message, self.max_message_size)?;
+				match frame.opcode {
+					Opcode::Ping => self.push_pending_response(OwnedMessage::Pong(frame.data))?,
+					Opcode::Close => {
+						let echo = echo_close(&frame.data);
+						self.push_pending_response(echo)?;
+						return Ok(vec![frame]);
+					}
+					_ => {}
+				}
+			};
 
 			if first.opcode == Opcode::Continuation {
-				return Err(WebSocketError::ProtocolError("Unexpected continuation data frame opcode",),);
+				return Err(WebSocketError::ProtocolViolation(
+					1002,
+					"Unexpected continuation data frame opcode",
+				));
 			}
 
 			let finished = first.finished;
+			tracker.account(first.data.len(), self.max_dataframes_per_message, self.max_message_size)?;
 			self.buffer.push(first);
 			finished
 		} else {
@@ -156,20 +602,326 @@ impl ws::Receiver for Receiver {
 
 		while !finished {
 			let next = self.recv_dataframe(reader, uuid)?;
-			finished = next.finished;
 
 			match next.opcode as u8 {
 				// Continuation opcode
-				0 => self.buffer.push(next),
+				0 => {
+					finished = next.finished;
+					if let Err(e) =
+						tracker.account(next.data.len(), self.max_dataframes_per_message, self.max_message_size)
+					{
+						self.buffer.clear();
+						return Err(e);
+					}
+					self.buffer.push(next)
+				}
 				// Control frame
 				8...15 => {
-					return Ok(vec![next]);
+					if !self.auto_respond_control_frames {
+						return Ok(vec![next]);
+					}
+
+					// count control frames against the per-message frame cap too, so a
+					// flood of interleaved Pings can't grow `pending_responses` without bound
+					if let Err(e) =
+						tracker.account(0, self.max_dataframes_per_message, self.max_message_size)
+					{
+						self.buffer.clear();
+						return Err(e);
+					}
+
+					match next.opcode {
+						Opcode::Ping => {
+							self.push_pending_response(OwnedMessage::Pong(next.data))?;
+							// keep reading for the data message; Ping doesn't affect `finished`
+						}
+						Opcode::Close => {
+							let echo = echo_close(&next.data);
+							self.push_pending_response(echo)?;
+							// a peer that sent Close isn't going to send more message data;
+							// surface it to the caller instead of waiting for more frames
+							return Ok(vec![next]);
+						}
+						_ => {}
+					}
 				}
 				// Others
-				_ => return Err(WebSocketError::ProtocolError("Unexpected data frame opcode")),
+				_ => {
+					return Err(WebSocketError::ProtocolViolation(1002, "Unexpected data frame opcode"))
+				}
 			}
 		}
 
-		Ok(::std::mem::replace(&mut self.buffer, Vec::new()))
+		let frames = ::std::mem::replace(&mut self.buffer, Vec::new());
+
+		if self.deflate_mode == DeflateMode::Disabled || !frames[0].reserved[0] {
+			return Ok(frames);
+		}
+
+		let opcode = frames[0].opcode;
+		let compressed: Vec<u8> = frames.into_iter().flat_map(|frame| frame.data).collect();
+		let data = self.inflate_message(&compressed)?;
+
+		Ok(vec![
+			DataFrame {
+				finished: true,
+				reserved: [false, false, false],
+				opcode: opcode,
+				data: data,
+			},
+		])
+	}
+}
+
+/// Builds the `OwnedMessage::Close` to echo back for a received Close frame,
+/// mirroring its status code and reason per RFC 6455 §5.5.1 instead of discarding them.
+fn echo_close(payload: &[u8]) -> OwnedMessage {
+	if payload.len() < 2 {
+		return OwnedMessage::Close(None);
+	}
+
+	let status_code = ((payload[0] as u16) << 8) | payload[1] as u16;
+	let reason = String::from_utf8_lossy(&payload[2..]).into_owned();
+
+	OwnedMessage::Close(Some(CloseData {
+		status_code: status_code,
+		reason: reason,
+	}))
+}
+
+/// Converts a `WebSocketError` produced while reading data frames into the
+/// `OwnedMessage::Close` a well-behaved endpoint should send back before tearing
+/// down the connection, if the error carries an applicable close code. Returns
+/// `None` for errors (e.g. I/O errors) that don't map to a close code.
+pub fn close_message_for_error(error: &WebSocketError) -> Option<OwnedMessage> {
+	let (status_code, reason) = match *error {
+		WebSocketError::ProtocolViolation(code, reason) => (code, reason),
+		WebSocketError::SizeLimitExceeded(reason) => (1009, reason),
+		WebSocketError::CompressionError(reason) => (1007, reason),
+		_ => return None,
+	};
+
+	Some(OwnedMessage::Close(Some(CloseData {
+		status_code: status_code,
+		reason: reason.to_owned(),
+	})))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::{Compress, Compression, FlushCompress};
+	use std::io::Cursor;
+
+	/// Compresses `data` the way a permessage-deflate sender would: raw DEFLATE,
+	/// `Z_SYNC_FLUSH`, with the resulting empty-stored-block trailer stripped off
+	/// (our inflate path adds it back before inflating).
+	fn compress_for_permessage_deflate(data: &[u8]) -> Vec<u8> {
+		let mut compress = Compress::new(Compression::default(), false);
+		let mut output = vec![0u8; data.len() + 256];
+		let produced = compress
+			.compress(data, &mut output, FlushCompress::Sync)
+			.map(|_| compress.total_out() as usize)
+			.expect("compress");
+		output.truncate(produced - 4);
+		output
+	}
+
+	#[test]
+	fn inflate_message_context_takeover_round_trips_multiple_messages() {
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		receiver.set_deflate_mode(DeflateMode::ContextTakeover);
+
+		let first = compress_for_permessage_deflate(b"hello");
+		let second = compress_for_permessage_deflate(b"world");
+
+		// the same `Decompress` stream is reused across these two calls; this is the
+		// case that panicked/corrupted output before the cumulative-offset fix
+		assert_eq!(receiver.inflate_message(&first).unwrap(), b"hello");
+		assert_eq!(receiver.inflate_message(&second).unwrap(), b"world");
+	}
+
+	#[test]
+	fn inflate_message_no_context_takeover_resets_between_messages() {
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		receiver.set_deflate_mode(DeflateMode::NoContextTakeover);
+
+		let first = compress_for_permessage_deflate(b"hello");
+		let second = compress_for_permessage_deflate(b"world");
+
+		assert_eq!(receiver.inflate_message(&first).unwrap(), b"hello");
+		assert_eq!(receiver.inflate_message(&second).unwrap(), b"world");
+	}
+
+	#[test]
+	fn echo_close_preserves_status_code_and_reason() {
+		let mut payload = vec![0x03, 0xEA]; // 1002, big-endian
+		payload.extend_from_slice(b"bye");
+
+		match echo_close(&payload) {
+			OwnedMessage::Close(Some(data)) => {
+				assert_eq!(data.status_code, 1002);
+				assert_eq!(data.reason, "bye");
+			}
+			other => panic!("expected Close(Some(..)), got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn echo_close_falls_back_to_none_for_short_payload() {
+		match echo_close(&[]) {
+			OwnedMessage::Close(None) => {}
+			other => panic!("expected Close(None), got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn message_size_tracker_rejects_too_many_frames() {
+		let mut tracker = MessageSizeTracker::new();
+		assert!(tracker.account(0, 2, usize::max_value()).is_ok());
+		assert!(tracker.account(0, 2, usize::max_value()).is_ok());
+		assert!(tracker.account(0, 2, usize::max_value()).is_err());
+	}
+
+	#[test]
+	fn message_size_tracker_rejects_oversized_message() {
+		let mut tracker = MessageSizeTracker::new();
+		// each frame counts its payload plus FRAME_OVERHEAD_BYTES: 24, then 48
+		assert!(tracker.account(10, usize::max_value(), 40).is_ok());
+		assert!(tracker.account(10, usize::max_value(), 40).is_err());
+	}
+
+	#[test]
+	fn close_message_for_error_maps_known_variants_only() {
+		match close_message_for_error(&WebSocketError::ProtocolViolation(1002, "bad")) {
+			Some(OwnedMessage::Close(Some(data))) => assert_eq!(data.status_code, 1002),
+			other => panic!("unexpected: {:?}", other),
+		}
+
+		let io_err = WebSocketError::IoError(IoError::new(IoErrorKind::Other, "boom"));
+		assert!(close_message_for_error(&io_err).is_none());
+	}
+
+	/// Encodes a single unmasked RFC 6455 data frame, which is all these tests need:
+	/// payloads short enough to use the one-byte length form.
+	fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+		let mut out = Vec::new();
+		out.push((if fin { 0x80 } else { 0x00 }) | (opcode & 0x0F));
+
+		let len = payload.len();
+		if len < 126 {
+			out.push(len as u8);
+		} else if len <= 0xFFFF {
+			out.push(126);
+			out.push((len >> 8) as u8);
+			out.push(len as u8);
+		} else {
+			out.push(127);
+			for i in (0..8).rev() {
+				out.push((len >> (8 * i)) as u8);
+			}
+		}
+
+		out.extend_from_slice(payload);
+		out
+	}
+
+	#[test]
+	fn message_reader_reassembles_a_fragmented_message() {
+		let mut bytes = encode_frame(false, 1, b"Hel");
+		bytes.extend(encode_frame(true, 0, b"lo"));
+
+		let mut cursor = Cursor::new(bytes.as_slice());
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+
+		let mut out = Vec::new();
+		receiver
+			.message_reader(&mut cursor)
+			.read_to_end(&mut out)
+			.expect("reads the whole message");
+
+		assert_eq!(out, b"Hello");
+	}
+
+	#[test]
+	fn message_reader_auto_responds_to_interleaved_ping() {
+		let mut bytes = encode_frame(false, 1, b"Hel");
+		bytes.extend(encode_frame(true, 9, b"ping-data"));
+		bytes.extend(encode_frame(true, 0, b"lo"));
+
+		let mut cursor = Cursor::new(bytes.as_slice());
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		receiver.set_auto_respond_to_control_frames(true);
+
+		let mut out = Vec::new();
+		receiver
+			.message_reader(&mut cursor)
+			.read_to_end(&mut out)
+			.expect("reads the whole message, skipping the ping");
+
+		assert_eq!(out, b"Hello");
+
+		let mut pending = receiver.take_pending_responses();
+		match pending.pop_front() {
+			Some(OwnedMessage::Pong(data)) => assert_eq!(data, b"ping-data"),
+			other => panic!("expected a queued Pong, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn message_reader_rejects_non_continuation_frame_after_the_first() {
+		let mut bytes = encode_frame(false, 1, b"Hel");
+		bytes.extend(encode_frame(true, 1, b"lo"));
+
+		let mut cursor = Cursor::new(bytes.as_slice());
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+
+		let mut out = Vec::new();
+		let err = receiver
+			.message_reader(&mut cursor)
+			.read_to_end(&mut out)
+			.expect_err("a non-continuation frame mid-message must error");
+		assert_eq!(err.kind(), IoErrorKind::InvalidData);
+	}
+
+	/// A `Read` that answers `WouldBlock` once before serving its bytes, to exercise
+	/// `try_recv_dataframe`'s non-blocking resume path.
+	struct FlakyReader<'a> {
+		data: &'a [u8],
+		position: usize,
+		fail_once: bool,
+	}
+
+	impl<'a> Read for FlakyReader<'a> {
+		fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+			if self.fail_once {
+				self.fail_once = false;
+				return Err(IoError::new(IoErrorKind::WouldBlock, "would block"));
+			}
+
+			let remaining = &self.data[self.position..];
+			let n = ::std::cmp::min(buf.len(), remaining.len());
+			buf[..n].copy_from_slice(&remaining[..n]);
+			self.position += n;
+			Ok(n)
+		}
+	}
+
+	#[test]
+	fn try_recv_dataframe_returns_none_on_would_block_then_resumes() {
+		let payload = encode_frame(true, 1, b"hi");
+		let mut reader = FlakyReader { data: &payload, position: 0, fail_once: true };
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+
+		let first = receiver
+			.try_recv_dataframe(&mut reader)
+			.expect("WouldBlock isn't an error");
+		assert!(first.is_none());
+
+		let second = receiver
+			.try_recv_dataframe(&mut reader)
+			.expect("resumes cleanly")
+			.expect("yields the frame once data is available");
+		assert_eq!(second.data, b"hi");
 	}
 }